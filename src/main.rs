@@ -3,53 +3,298 @@
 
 //! A super simple implementation of RFC8686 DNS over HTTPS that proxies normal UDP/53
 //! requests into a DoH-enabled server, possibly over a SOCKS proxy. This client isn't
-//! meant to be exposed over the internet, it can only handle one client at the time and
-//! can memory leak very easily.
+//! meant to be exposed over the internet.
 //!
 //! If you need a small-footprint service that proxies DNS queries over HTTPS and nothing
 //! else, this may be a good fit for you.
 
-use clap::Parser;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use clap::{Parser, ValueEnum};
+use futures::future::select_ok;
 use log::debug;
+use rand::Rng;
 use reqwest::{Client, Proxy};
 use simplelog::{Config, SharedLogger};
 use std::{
-    collections::HashMap,
-    net::{SocketAddr, UdpSocket},
-    time::Instant,
+    collections::{HashMap, HashSet},
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-type Cache = HashMap<Vec<u8>, (Instant, Vec<u8>)>;
+use tokio::{
+    net::UdpSocket,
+    sync::{Mutex, Notify, RwLock},
+};
+
+/// A single cached response: the instant it was inserted at, the TTL (in seconds)
+/// taken from the DNS answer that produced it, the raw DoH response (header included),
+/// and a reference bit used by the CLOCK eviction policy.
+struct CacheEntry {
+    key: Vec<u8>,
+    inserted_at: Instant,
+    ttl: u64,
+    payload: Vec<u8>,
+    referenced: bool,
+}
+
+/// A fixed-capacity cache bounded by entry count, evicted with the CLOCK ("second
+/// chance") approximation to LRU: every slot carries a reference bit that gets set on
+/// access, and an insert that finds the cache full advances a hand clearing reference
+/// bits until it lands on an unreferenced slot, which it evicts.
+///
+/// This gives near-LRU behavior without the cost of reordering entries on every hit,
+/// and guarantees steady-state memory regardless of query volume.
+struct Cache {
+    capacity: usize,
+    slots: Vec<Option<CacheEntry>>,
+    index: HashMap<Vec<u8>, usize>,
+    hand: usize,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Cache {
+            capacity: capacity.max(1),
+            slots: Vec::new(),
+            index: HashMap::new(),
+            hand: 0,
+        }
+    }
+
+    /// Looks up `key`, setting the entry's reference bit on a hit.
+    fn get(&mut self, key: &[u8]) -> Option<(Instant, u64, &Vec<u8>)> {
+        let &idx = self.index.get(key)?;
+        let entry = self.slots[idx].as_mut().unwrap();
+        entry.referenced = true;
+        Some((entry.inserted_at, entry.ttl, &entry.payload))
+    }
+
+    /// Inserts or refreshes `key`, evicting a slot via the CLOCK hand if the cache is
+    /// already at capacity.
+    fn insert(&mut self, key: Vec<u8>, ttl: u64, payload: Vec<u8>) {
+        if let Some(&idx) = self.index.get(&key) {
+            let entry = self.slots[idx].as_mut().unwrap();
+            entry.inserted_at = Instant::now();
+            entry.ttl = ttl;
+            entry.payload = payload;
+            entry.referenced = true;
+            return;
+        }
+
+        let entry = CacheEntry {
+            key: key.clone(),
+            inserted_at: Instant::now(),
+            ttl,
+            payload,
+            referenced: false,
+        };
+
+        if self.slots.len() < self.capacity {
+            self.index.insert(key, self.slots.len());
+            self.slots.push(Some(entry));
+            return;
+        }
+
+        let idx = self.evict();
+        self.index.insert(key, idx);
+        self.slots[idx] = Some(entry);
+    }
+
+    /// Advances the clock hand, clearing reference bits along the way, until it finds a
+    /// slot whose reference bit is already cleared (or empty), and returns that slot's
+    /// index. The slot's previous occupant, if any, is removed from the index.
+    fn evict(&mut self) -> usize {
+        loop {
+            let idx = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            match &mut self.slots[idx] {
+                None => return idx,
+                Some(entry) if entry.referenced => entry.referenced = false,
+                Some(_) => {
+                    let evicted = self.slots[idx].take().unwrap();
+                    self.index.remove(&evicted.key);
+                    return idx;
+                }
+            }
+        }
+    }
+
+    /// Drops every entry whose TTL elapsed more than `grace_secs` ago. A `grace_secs`
+    /// of zero evicts as soon as the TTL runs out; a positive value keeps stale entries
+    /// around a little longer so they remain available for serve-stale.
+    fn retain_expired_beyond(&mut self, grace_secs: u64) {
+        for slot in self.slots.iter_mut() {
+            let expired = matches!(slot, Some(entry) if entry.inserted_at.elapsed().as_secs() > entry.ttl + grace_secs);
+            if expired {
+                let entry = slot.take().unwrap();
+                self.index.remove(&entry.key);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.slots.clear();
+        self.index.clear();
+        self.hand = 0;
+    }
+}
 
 /// RFC-8686 mandates that the content-type header should be set to application/dns-message
 const POST_CONTENT_TYPE_KEY: &str = "Content-Type";
 const POST_CONTENT_TYPE_VALUE: &str = "application/dns-message";
+const ACCEPT_HEADER_KEY: &str = "Accept";
+
+/// Which HTTP method to use when talking to the DoH upstream, per RFC 8484.
+#[derive(Clone, Copy, ValueEnum)]
+enum DohMethod {
+    /// Send the raw wire-format query as the request body.
+    Post,
+    /// Send the raw wire-format query base64url-encoded (no padding) in the `dns` query
+    /// parameter, with no body. Cacheable by intermediary HTTP caches.
+    Get,
+}
+
+/// Once a cached entry's remaining TTL drops below this many seconds, we start shaving
+/// a small random jitter off the TTL handed to clients, so a burst of clients that got
+/// the same answer doesn't see it expire, and query again, at the exact same instant.
+const JITTER_THRESHOLD_SECS: u64 = 30;
+/// The largest jitter we'll ever subtract from a remaining TTL.
+const JITTER_MAX_SECS: u64 = 5;
+
+/// How much longer an expired entry is kept around after its TTL runs out, so it can
+/// still be served stale if the upstream is unreachable.
+const STALE_GRACE_SECS: u64 = 300;
+
+/// The UDP payload size we advertise via EDNS0 when falling back to a plain resolver.
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// How to respond to a query for a blocked domain.
+#[derive(Clone, Copy, ValueEnum)]
+enum BlockMode {
+    /// Answer with NXDOMAIN, as if the name didn't exist.
+    Nxdomain,
+    /// Answer an A/AAAA query with a record pointing at 0.0.0.0 / ::, sinkholing it;
+    /// queries of any other type get NXDOMAIN.
+    Sinkhole,
+}
+
+/// A set of blocked domains, loaded from a file of one domain per line. Matching is by
+/// suffix, so an entry for `example.com` also blocks `ads.example.com`.
+struct Blocklist {
+    domains: HashSet<String>,
+}
+
+impl Blocklist {
+    fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let domains = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.trim_end_matches('.').to_lowercase())
+            .collect();
+        Ok(Blocklist { domains })
+    }
+
+    /// Returns whether `name` (with a trailing dot, as produced by
+    /// `get_query_names`) matches an entry in the list, or is a subdomain of one.
+    fn is_blocked(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let mut suffix = name.as_str();
+        loop {
+            if self.domains.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, rest)) => suffix = rest,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// How many of the best-ranked upstreams to race concurrently on a cache miss.
+const RACE_FANOUT: usize = 3;
+
+/// A rolling latency/failure score for one upstream, used to rank upstreams so a
+/// consistently slow or failing one is raced less often over time. Lower is better.
+struct UpstreamScore {
+    ewma_latency_ms: f64,
+    failures: u32,
+}
+
+impl UpstreamScore {
+    const EWMA_ALPHA: f64 = 0.2;
+    /// How much a single failure weighs against latency, in equivalent milliseconds.
+    const FAILURE_PENALTY_MS: f64 = 1000.0;
+
+    fn new() -> Self {
+        UpstreamScore {
+            ewma_latency_ms: 0.0,
+            failures: 0,
+        }
+    }
+
+    fn record_success(&mut self, latency: Duration) {
+        let sample_ms = latency.as_secs_f64() * 1000.0;
+        self.ewma_latency_ms = if self.ewma_latency_ms == 0.0 {
+            sample_ms
+        } else {
+            Self::EWMA_ALPHA * sample_ms + (1.0 - Self::EWMA_ALPHA) * self.ewma_latency_ms
+        };
+        self.failures /= 2;
+    }
+
+    fn record_failure(&mut self) {
+        self.failures += 1;
+    }
+
+    fn rank(&self) -> f64 {
+        self.ewma_latency_ms + self.failures as f64 * Self::FAILURE_PENALTY_MS
+    }
+}
+
+/// Everything a spawned request handler needs, shared across all in-flight requests.
+struct SharedState {
+    client: Client,
+    /// Every configured DoH upstream, indexed the same way as `scores`.
+    remotes: Vec<String>,
+    scores: Vec<Mutex<UpstreamScore>>,
+    method: DohMethod,
+    cache_enabled: bool,
+    cache: RwLock<Cache>,
+    /// Keys currently being fetched from upstream, so concurrent queries for the same
+    /// name can wait on a single fetch instead of firing duplicate POSTs.
+    inflight: Mutex<HashMap<Vec<u8>, Arc<Notify>>>,
+    /// How long to wait on the upstream DoH request before giving up on it.
+    timeout: Duration,
+    /// Whether an expired-but-present cache entry may be served when the upstream
+    /// fails.
+    serve_stale: bool,
+    /// A conventional UDP resolver to fall back to when the DoH upstream fails and no
+    /// stale entry is available.
+    fallback_resolver: Option<SocketAddr>,
+    /// Domains to sinkhole without ever contacting the cache or upstream.
+    blocklist: Option<Blocklist>,
+    block_mode: BlockMode,
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
 
-    // Gets the cache ttl param
-    let ttl = if args.cache {
-        args.cache_ttl
-    } else {
-        0 // this just disables caching (every entry is stale, no metter what)
-    };
-
     // Init global logger
     if args.log_queries {
         init_logger(None, log::LevelFilter::Debug, true);
     }
 
     // The UDP socket we'll listen for incomming DNS requests
-    let listener = UdpSocket::bind(args.addr_bind).unwrap();
-
-    // A cache used for domains speeding up dns requests
-    let mut cache: Cache = HashMap::new();
-
-    // Allocate a 65kb buffer, this should be more than enougth for most applications
-    let mut buffer = [0; 1 << 16];
+    let listener = Arc::new(UdpSocket::bind(args.addr_bind).await?);
 
-    // A https client we use to make DoH requests
+    // A https client we use to make DoH requests, shared and reused by every task since
+    // it already pools its own connections
     let client = if let Some(proxy) = args.proxy {
         reqwest::Client::builder()
             .proxy(Proxy::all(proxy)?)
@@ -58,82 +303,559 @@ async fn main() -> anyhow::Result<()> {
         reqwest::Client::builder().build()?
     };
 
-    // We only handle one client at the time
-    while let Ok((count, origin)) = listener.recv_from(&mut buffer) {
+    let blocklist = args.blocklist.as_deref().map(Blocklist::load).transpose()?;
+    let scores = args.remote.iter().map(|_| Mutex::new(UpstreamScore::new())).collect();
+
+    let state = Arc::new(SharedState {
+        client,
+        remotes: args.remote,
+        scores,
+        method: args.method,
+        cache_enabled: args.cache,
+        cache: RwLock::new(Cache::new(args.cache_size)),
+        inflight: Mutex::new(HashMap::new()),
+        timeout: Duration::from_secs(args.timeout),
+        serve_stale: args.serve_stale,
+        fallback_resolver: args.fallback_resolver,
+        blocklist,
+        block_mode: args.block_mode,
+    });
+
+    // Allocate a 65kb buffer, this should be more than enougth for most applications
+    let mut buffer = [0; 1 << 16];
+
+    // Spawn a task per datagram so a slow upstream lookup for one client never stalls
+    // the others
+    loop {
+        let (count, origin) = listener.recv_from(&mut buffer).await?;
         let request = buffer[0..count].to_vec();
 
-        // Retrieve the query paramenter to log
-        let mut name = String::new();
-        get_query_names(&request[12..], &mut name);
+        let state = state.clone();
+        let listener = listener.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_request(state, listener, request, origin).await {
+                debug!("failed to handle request from {origin}: {err}");
+            }
+        });
+    }
+}
+
+/// Resolves a single incoming query, consulting (and populating) the shared cache, and
+/// sends the answer back to `origin`.
+async fn handle_request(
+    state: Arc<SharedState>,
+    listener: Arc<UdpSocket>,
+    request: Vec<u8>,
+    origin: SocketAddr,
+) -> anyhow::Result<()> {
+    // Retrieve the query paramenter to log
+    let mut name = String::new();
+    get_query_names(&request[12..], &mut name);
 
-        // Remove expired entries from our cache
-        invalidate_cache(&mut cache, ttl, false);
-        cache_hit(&client, &mut cache, &args.remote, &name, request.clone()).await?;
+    if let Some(blocklist) = &state.blocklist {
+        if blocklist.is_blocked(&name) {
+            debug!("{} BLOCKED", name);
+            let mut res = synthesize_block_response(&request, state.block_mode);
+            res[0] = request[0];
+            res[1] = request[1];
+            listener.send_to(res.as_slice(), origin).await?;
+            return Ok(());
+        }
+    }
 
-        let (_, res) = cache.get(&request[2..]).unwrap();
-        let res = request[0..2]
-            .iter()
-            .chain(res.into_iter())
-            .copied()
-            .collect::<Vec<_>>();
-        listener.send_to(res.as_slice(), origin)?;
+    let grace = if state.serve_stale { STALE_GRACE_SECS } else { 0 };
+    if state.cache_enabled {
+        // Remove entries whose TTL (plus any serve-stale grace period) has fully
+        // elapsed
+        state.cache.write().await.retain_expired_beyond(grace);
+    } else {
+        // Caching is disabled, never serve anything we had cached before
+        state.cache.write().await.clear();
     }
 
+    let key = request[2..].to_vec();
+    let outcome = resolve(&state, &name, &key, request.clone()).await?;
+
+    let mut res = match outcome {
+        ResolveOutcome::Cached {
+            inserted_at,
+            ttl,
+            mut payload,
+        } => {
+            let remaining = remaining_ttl_with_jitter(ttl, inserted_at.elapsed().as_secs());
+            let _ = rewrite_answer_ttls(&mut payload, remaining as u32);
+            payload
+        }
+        ResolveOutcome::Raw(payload) => payload,
+    };
+    res[0] = request[0];
+    res[1] = request[1];
+
+    listener.send_to(res.as_slice(), origin).await?;
     Ok(())
 }
 
-/// Transverses a cache and retains only entryies that are not stale yet
-fn invalidate_cache(cache: &mut Cache, ttl: u64, force: bool) {
-    let mut new_cache = Cache::new();
-    for key in cache.keys().cloned() {
-        let value = cache.get(&key).unwrap();
-        if value.0.elapsed().as_secs() > ttl || !force {
-            new_cache.insert(key.clone(), value.clone());
+/// What resolving a query produced: either a cache entry (fresh or served stale),
+/// whose TTL still needs to be rewritten to the remaining time, or a raw response
+/// (from the UDP fallback resolver, or a synthesized SERVFAIL) that should be
+/// forwarded as-is.
+enum ResolveOutcome {
+    Cached {
+        inserted_at: Instant,
+        ttl: u64,
+        payload: Vec<u8>,
+    },
+    Raw(Vec<u8>),
+}
+
+/// Resolves `key`, coalescing concurrent requests for the same key into a single
+/// upstream fetch: whichever task gets here first becomes the leader and performs the
+/// fetch (with timeout, serve-stale and UDP-fallback handling), while the rest wait on
+/// its `Notify` instead of firing duplicate POSTs.
+async fn resolve(
+    state: &SharedState,
+    name: &str,
+    key: &[u8],
+    request: Vec<u8>,
+) -> anyhow::Result<ResolveOutcome> {
+    if let Some(outcome) = fresh_cached(state, key).await {
+        debug!("{} HIT", name);
+        return Ok(outcome);
+    }
+
+    let (notify, is_leader) = {
+        let mut inflight = state.inflight.lock().await;
+        if let Some(existing) = inflight.get(key) {
+            (existing.clone(), false)
+        } else {
+            let notify = Arc::new(Notify::new());
+            inflight.insert(key.to_vec(), notify.clone());
+            (notify, true)
         }
+    };
+
+    if !is_leader {
+        debug!("{} MISS (coalesced)", name);
+        let notified = notify.notified();
+        // The leader may have already finished between the cache check above and
+        // getting here; Notify buffers a notify_waiters() call made after this
+        // `notified()` future was created, so this re-check only needs to cover the
+        // case where the fetch had already completed before we got in line.
+        if let Some(outcome) = fresh_cached(state, key).await {
+            return Ok(outcome);
+        }
+        if tokio::time::timeout(state.timeout, notified).await.is_ok() {
+            if let Some(outcome) = fresh_cached(state, key).await {
+                return Ok(outcome);
+            }
+        }
+        // Either the wait timed out (the leader is taking unexpectedly long, or its
+        // notify_waiters() raced ahead of us constructing `notified` above and we'd
+        // have waited forever), or the leader hit a fallback path that isn't cached
+        // (serve-stale or a raw passthrough); just resolve independently rather than
+        // wait forever.
+        return fetch_with_fallbacks(state, key, request).await;
     }
-    *cache = new_cache;
+
+    debug!("{} MISS", name);
+    let outcome = fetch_with_fallbacks(state, key, request).await;
+    state.inflight.lock().await.remove(key);
+    notify.notify_waiters();
+    outcome
 }
 
-/// If we don't have a particular element cached, get it and insert in our local cache
-async fn cache_miss<'a>(
-    client: &Client,
-    cache: &mut Cache,
-    remote: &str,
+/// Returns the cached entry for `key` if one is present and its TTL hasn't elapsed yet.
+async fn fresh_cached(state: &SharedState, key: &[u8]) -> Option<ResolveOutcome> {
+    let mut cache = state.cache.write().await;
+    let (inserted_at, ttl, payload) = cache.get(key)?;
+    if inserted_at.elapsed().as_secs() > ttl {
+        return None;
+    }
+    Some(ResolveOutcome::Cached {
+        inserted_at,
+        ttl,
+        payload: payload.clone(),
+    })
+}
+
+/// Returns the cached entry for `key` regardless of whether its TTL has elapsed,
+/// for serve-stale fallback.
+async fn stale_cached(state: &SharedState, key: &[u8]) -> Option<ResolveOutcome> {
+    let mut cache = state.cache.write().await;
+    let (inserted_at, ttl, payload) = cache.get(key)?;
+    Some(ResolveOutcome::Cached {
+        inserted_at,
+        ttl,
+        payload: payload.clone(),
+    })
+}
+
+/// Tries the DoH upstream (bounded by `state.timeout`), then falls back to a stale
+/// cache entry, then a conventional UDP resolver, then finally a synthesized SERVFAIL.
+async fn fetch_with_fallbacks(
+    state: &SharedState,
+    key: &[u8],
     request: Vec<u8>,
-) -> anyhow::Result<()> {
-    let post = client
-        .post(remote)
-        .body(request.clone())
-        .header(POST_CONTENT_TYPE_KEY, POST_CONTENT_TYPE_VALUE)
-        .send()
-        .await?;
-    let mut body = post.bytes().await?.to_vec();
-    cache.insert(
-        request[2..].to_vec(),
-        (Instant::now(), body.drain(2..).collect()),
-    );
-    Ok(())
+) -> anyhow::Result<ResolveOutcome> {
+    let upstream = tokio::time::timeout(state.timeout, race_upstreams(state, request.clone())).await;
+
+    if let Ok(Ok(body)) = upstream {
+        let ttl = min_answer_ttl(&body).unwrap_or(0);
+        let inserted_at = Instant::now();
+        state.cache.write().await.insert(key.to_vec(), ttl, body.clone());
+        return Ok(ResolveOutcome::Cached {
+            inserted_at,
+            ttl,
+            payload: body,
+        });
+    }
+
+    if state.serve_stale {
+        if let Some(outcome) = stale_cached(state, key).await {
+            debug!("serving stale entry, upstream DoH request failed");
+            return Ok(outcome);
+        }
+    }
+
+    if let Some(resolver) = state.fallback_resolver {
+        match udp_fallback(resolver, &request, state.timeout).await {
+            Ok(response) => return Ok(ResolveOutcome::Raw(response)),
+            Err(err) => debug!("UDP fallback resolver {resolver} failed: {err}"),
+        }
+    }
+
+    Ok(ResolveOutcome::Raw(synthesize_servfail(&request)))
 }
 
-/// Checks if we have this entry on cache, if we do, this function is a no-op.
-/// If we don't have this entry cached, we request it.
-///
-/// After calling this function, you're garanteed to have that entry cached, so
-/// cache.get(Key).unwrap() will never panic
-async fn cache_hit(
+/// Computes how many seconds are left before `ttl` (counted from `elapsed_secs` ago)
+/// runs out, clamped at zero. Once the remaining time drops below
+/// `JITTER_THRESHOLD_SECS`, a small random jitter is subtracted so clients handed the
+/// same entry don't all see it expire, and query again, in lockstep. Only the TTL
+/// reported to clients is jittered; the entry itself still expires from our cache
+/// once `ttl` seconds have elapsed.
+fn remaining_ttl_with_jitter(ttl: u64, elapsed_secs: u64) -> u64 {
+    let remaining = ttl.saturating_sub(elapsed_secs);
+    if remaining == 0 || remaining >= JITTER_THRESHOLD_SECS {
+        return remaining;
+    }
+    let jitter = rand::thread_rng().gen_range(0..=JITTER_MAX_SECS.min(remaining));
+    remaining - jitter
+}
+
+/// Skips a single DNS name starting at `offset`: either a sequence of length-prefixed
+/// labels terminated by a zero-length byte, or a 2-byte 0xC0-prefixed compression
+/// pointer. Returns the offset just past the name, or `None` if `msg` is too short to
+/// contain a well-formed name there.
+fn skip_name(msg: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(offset)? as usize;
+        if len == 0 {
+            return Some(offset + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return (offset + 2 <= msg.len()).then_some(offset + 2);
+        }
+        offset += 1 + len;
+    }
+}
+
+/// Walks past the header and the `qdcount` questions of `msg`, returning the offset
+/// where the answer section begins, or `None` if `msg` is too short to hold them.
+fn skip_question_section(msg: &[u8], qdcount: u16) -> Option<usize> {
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        offset = skip_name(msg, offset)?;
+        offset += 4; // QTYPE + QCLASS
+        if offset > msg.len() {
+            return None;
+        }
+    }
+    Some(offset)
+}
+
+/// Returns the minimum TTL across every answer RR in `msg`, or `None` if it has no
+/// answers or isn't even long enough to be a DNS message.
+fn min_answer_ttl(msg: &[u8]) -> Option<u64> {
+    let qdcount = u16::from_be_bytes([*msg.get(4)?, *msg.get(5)?]);
+    let ancount = u16::from_be_bytes([*msg.get(6)?, *msg.get(7)?]);
+    let mut offset = skip_question_section(msg, qdcount)?;
+
+    let mut min_ttl = None;
+    for _ in 0..ancount {
+        offset = skip_name(msg, offset)?;
+        offset += 4; // TYPE + CLASS
+        let ttl = u32::from_be_bytes(msg.get(offset..offset + 4)?.try_into().ok()?) as u64;
+        offset += 4;
+        let rdlength = u16::from_be_bytes([*msg.get(offset)?, *msg.get(offset + 1)?]) as usize;
+        offset += 2 + rdlength;
+        if offset > msg.len() {
+            return None;
+        }
+
+        min_ttl = Some(min_ttl.map_or(ttl, |min: u64| min.min(ttl)));
+    }
+    min_ttl
+}
+
+/// Rewrites every answer RR's 4-byte TTL field in `msg` to `remaining`, so a client
+/// reading a cached response sees the actual time left instead of the original,
+/// possibly stale, TTL. Returns `None` (leaving `msg` partially rewritten) if it turns
+/// out to be too short to walk. Responses that `min_answer_ttl` rejected are still
+/// cached (with a TTL of 0) and pass through here, so `handle_request` ignores a `None`
+/// and forwards the message as it stands.
+fn rewrite_answer_ttls(msg: &mut [u8], remaining: u32) -> Option<()> {
+    let qdcount = u16::from_be_bytes([*msg.get(4)?, *msg.get(5)?]);
+    let ancount = u16::from_be_bytes([*msg.get(6)?, *msg.get(7)?]);
+    let mut offset = skip_question_section(msg, qdcount)?;
+
+    let ttl_bytes = remaining.to_be_bytes();
+    for _ in 0..ancount {
+        offset = skip_name(msg, offset)?;
+        offset += 4; // TYPE + CLASS
+        msg.get_mut(offset..offset + 4)?.copy_from_slice(&ttl_bytes);
+        offset += 4;
+        let rdlength = u16::from_be_bytes([*msg.get(offset)?, *msg.get(offset + 1)?]) as usize;
+        offset += 2 + rdlength;
+        if offset > msg.len() {
+            return None;
+        }
+    }
+    Some(())
+}
+
+/// A single in-flight upstream fetch, boxed so `race_upstreams` can hold a
+/// heterogeneous collection of them for `select_ok`.
+type BoxedFetch<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Vec<u8>>> + Send + 'a>>;
+
+/// Races the best-ranked `RACE_FANOUT` upstreams concurrently, returning whichever
+/// valid response arrives first and updating each raced upstream's latency/failure
+/// score along the way. The losers are dropped (and their requests cancelled) once the
+/// first one succeeds.
+async fn race_upstreams(state: &SharedState, request: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let ranked = rank_upstreams(state).await;
+    let fanout = ranked.len().min(RACE_FANOUT);
+
+    let attempts: Vec<BoxedFetch> = ranked[..fanout]
+        .iter()
+        .map(|&idx| Box::pin(race_one(state, idx, request.clone())) as _)
+        .collect();
+
+    let (body, _losers) = select_ok(attempts).await?;
+    Ok(body)
+}
+
+/// Returns the indices of `state.remotes`, best (lowest score) first.
+async fn rank_upstreams(state: &SharedState) -> Vec<usize> {
+    let mut ranked = Vec::with_capacity(state.remotes.len());
+    for (idx, score) in state.scores.iter().enumerate() {
+        ranked.push((idx, score.lock().await.rank()));
+    }
+    ranked.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    ranked.into_iter().map(|(idx, _)| idx).collect()
+}
+
+/// Fetches `request` from `state.remotes[idx]`, recording its latency or failure in
+/// `state.scores[idx]`.
+async fn race_one(state: &SharedState, idx: usize, request: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let started = Instant::now();
+    let result = fetch_upstream(&state.client, state.method, &state.remotes[idx], request).await;
+    match &result {
+        Ok(_) => state.scores[idx].lock().await.record_success(started.elapsed()),
+        Err(_) => state.scores[idx].lock().await.record_failure(),
+    }
+    result
+}
+
+/// Fetches `request` from the upstream DoH server, using whichever HTTP method was
+/// configured, and returns the raw response bytes.
+async fn fetch_upstream(
     client: &Client,
-    cache: &mut Cache,
+    method: DohMethod,
     remote: &str,
-    name: &String,
     request: Vec<u8>,
-) -> anyhow::Result<()> {
-    if cache.contains_key(&request[2..].to_vec()) {
-        debug!("{} HIT", name);
-        return Ok(());
+) -> anyhow::Result<Vec<u8>> {
+    let response = match method {
+        DohMethod::Post => {
+            client
+                .post(remote)
+                .body(request.clone())
+                .header(POST_CONTENT_TYPE_KEY, POST_CONTENT_TYPE_VALUE)
+                .send()
+                .await?
+        }
+        DohMethod::Get => {
+            let encoded = URL_SAFE_NO_PAD.encode(&request);
+            client
+                .get(format!("{remote}?dns={encoded}"))
+                .header(ACCEPT_HEADER_KEY, POST_CONTENT_TYPE_VALUE)
+                .send()
+                .await?
+        }
+    };
+    let body = response.error_for_status()?.bytes().await?.to_vec();
+    if !is_valid_response(&body, &request) {
+        anyhow::bail!("upstream {remote} returned something that isn't a valid DNS response to our question");
     }
-    debug!("{} MISS", name);
-    cache_miss(client, cache, remote, request).await
+    Ok(body)
+}
+
+/// A minimal sanity check that `response` is shaped like a DNS response we can safely
+/// cache and forward as-is: long enough for a header, the QR (response) bit set, a
+/// question section that doesn't run past the end of the message, and that question
+/// section byte-for-byte matching `request`'s. The last check is what stops a captive
+/// portal, compromised upstream, or otherwise broken `--remote` from "winning" the race
+/// with a spoofed answer for a different name than the one we asked about; without it a
+/// well-formed but unrelated response would otherwise pass every other check here.
+fn is_valid_response(response: &[u8], request: &[u8]) -> bool {
+    if response.len() < 12 || response[2] & 0x80 == 0 {
+        return false;
+    }
+
+    let request_qdcount = u16::from_be_bytes([request[4], request[5]]);
+    let Some(request_question_end) = skip_question_section(request, request_qdcount) else {
+        return false;
+    };
+
+    let response_qdcount = u16::from_be_bytes([response[4], response[5]]);
+    let Some(response_question_end) = skip_question_section(response, response_qdcount) else {
+        return false;
+    };
+
+    response[12..response_question_end] == request[12..request_question_end]
+}
+
+/// Skips a single full RR (name, type, class, ttl, rdlength, rdata) starting at
+/// `offset`, returning the offset just past it, or `None` if `msg` is too short to hold
+/// it.
+fn skip_rr(msg: &[u8], offset: usize) -> Option<usize> {
+    let offset = skip_name(msg, offset)?;
+    let rdlength = u16::from_be_bytes([*msg.get(offset + 8)?, *msg.get(offset + 9)?]) as usize;
+    let end = offset + 10 + rdlength;
+    (end <= msg.len()).then_some(end)
+}
+
+/// Sets the EDNS0 UDP payload size on `request`'s OPT record before forwarding the
+/// query to a plain UDP resolver. A minimal OPT record is appended only when the query
+/// has no additional RRs at all; a query whose last additional RR isn't an OPT record
+/// is forwarded unmodified.
+fn with_edns_udp_payload_size(request: &[u8]) -> Vec<u8> {
+    let arcount = u16::from_be_bytes([request[10], request[11]]);
+    if arcount == 0 {
+        let mut msg = request.to_vec();
+        msg.push(0x00); // root NAME
+        msg.extend_from_slice(&41u16.to_be_bytes()); // TYPE = OPT
+        msg.extend_from_slice(&EDNS_UDP_PAYLOAD_SIZE.to_be_bytes()); // CLASS = UDP payload size
+        msg.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // extended RCODE + flags
+        msg.extend_from_slice(&0u16.to_be_bytes()); // RDLENGTH
+        msg[10..12].copy_from_slice(&1u16.to_be_bytes());
+        return msg;
+    }
+
+    rewrite_opt_class(request, arcount).unwrap_or_else(|| request.to_vec())
+}
+
+/// By convention the OPT record is the last additional RR; walks past everything
+/// before it and rewrites its CLASS field to `EDNS_UDP_PAYLOAD_SIZE`. Returns `None`
+/// if `request` turns out to be too short to walk, or if that last RR isn't a
+/// root-named OPT record, leaving the caller to forward it unmodified.
+fn rewrite_opt_class(request: &[u8], arcount: u16) -> Option<Vec<u8>> {
+    let qdcount = u16::from_be_bytes([request[4], request[5]]);
+    let ancount = u16::from_be_bytes([request[6], request[7]]);
+    let nscount = u16::from_be_bytes([request[8], request[9]]);
+    let mut offset = skip_question_section(request, qdcount)?;
+    for _ in 0..(ancount + nscount + (arcount - 1)) {
+        offset = skip_rr(request, offset)?;
+    }
+
+    // NAME = root, TYPE = OPT (41)
+    if request.get(offset..offset + 3)? != [0u8, 0, 41] {
+        return None;
+    }
+
+    let mut msg = request.to_vec();
+    let class_offset = offset + 1 + 2; // past NAME (root) + TYPE
+    msg.get_mut(class_offset..class_offset + 2)?
+        .copy_from_slice(&EDNS_UDP_PAYLOAD_SIZE.to_be_bytes());
+    Some(msg)
+}
+
+/// Falls back to a conventional UDP resolver at `resolver` when the DoH upstream is
+/// unavailable.
+async fn udp_fallback(
+    resolver: SocketAddr,
+    request: &[u8],
+    timeout: Duration,
+) -> anyhow::Result<Vec<u8>> {
+    let query = with_edns_udp_payload_size(request);
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.send_to(&query, resolver).await?;
+
+    let mut buffer = [0; 1 << 16];
+    let (count, _) = tokio::time::timeout(timeout, socket.recv_from(&mut buffer)).await??;
+    Ok(buffer[..count].to_vec())
+}
+
+/// Builds a SERVFAIL response echoing `request`'s ID and question, for when neither
+/// the DoH upstream nor the UDP fallback resolver could be reached.
+fn synthesize_servfail(request: &[u8]) -> Vec<u8> {
+    let qdcount = u16::from_be_bytes([request[4], request[5]]);
+    let question_end = skip_question_section(request, qdcount)
+        .unwrap_or(12)
+        .min(request.len());
+
+    let mut response = request[..question_end].to_vec();
+    response[2] |= 0x80; // QR = response
+    response[3] = (response[3] & 0xf0) | 0x02; // RCODE = SERVFAIL
+    response[6..8].copy_from_slice(&0u16.to_be_bytes()); // ANCOUNT
+    response[8..10].copy_from_slice(&0u16.to_be_bytes()); // NSCOUNT
+    response[10..12].copy_from_slice(&0u16.to_be_bytes()); // ARCOUNT
+    response
 }
+
+/// Synthesizes a response for a blocked query: either NXDOMAIN, or (in sinkhole mode,
+/// and only when the question is an A or AAAA query) an answer pointing at 0.0.0.0 /
+/// ::. Echoes `request`'s ID and question.
+fn synthesize_block_response(request: &[u8], mode: BlockMode) -> Vec<u8> {
+    const TYPE_A: u16 = 1;
+    const TYPE_AAAA: u16 = 28;
+
+    let qdcount = u16::from_be_bytes([request[4], request[5]]);
+    let question_end = skip_question_section(request, qdcount)
+        .unwrap_or(12)
+        .min(request.len());
+    let qtype = u16::from_be_bytes([request[question_end - 4], request[question_end - 3]]);
+
+    let mut response = request[..question_end].to_vec();
+    response[2] |= 0x80; // QR = response
+    response[6..8].copy_from_slice(&0u16.to_be_bytes()); // ANCOUNT
+    response[8..10].copy_from_slice(&0u16.to_be_bytes()); // NSCOUNT
+    response[10..12].copy_from_slice(&0u16.to_be_bytes()); // ARCOUNT
+
+    match (mode, qtype) {
+        (BlockMode::Sinkhole, TYPE_A) => append_sinkhole_answer(&mut response, TYPE_A, &[0, 0, 0, 0]),
+        (BlockMode::Sinkhole, TYPE_AAAA) => {
+            append_sinkhole_answer(&mut response, TYPE_AAAA, &[0; 16])
+        }
+        _ => {
+            response[3] = (response[3] & 0xf0) | 0x03; // RCODE = NXDOMAIN
+            response[6..8].copy_from_slice(&0u16.to_be_bytes()); // ANCOUNT
+        }
+    }
+    response
+}
+
+/// Appends a single answer RR pointing back at the question name (via a compression
+/// pointer to offset 12) with the given type and rdata, and sets ANCOUNT to 1.
+fn append_sinkhole_answer(response: &mut Vec<u8>, qtype: u16, rdata: &[u8]) {
+    response.extend_from_slice(&0xc00cu16.to_be_bytes()); // NAME = pointer to the question
+    response.extend_from_slice(&qtype.to_be_bytes());
+    response.extend_from_slice(&1u16.to_be_bytes()); // CLASS = IN
+    response.extend_from_slice(&0u32.to_be_bytes()); // TTL
+    response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    response.extend_from_slice(rdata);
+    response[6..8].copy_from_slice(&1u16.to_be_bytes()); // ANCOUNT
+}
+
 fn get_query_names(req: &[u8], acc: &mut String) {
     let len = req[0] as usize;
     if len == 0 {
@@ -178,19 +900,237 @@ fn init_logger(log_file: Option<&str>, log_level: log::LevelFilter, log_to_term:
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
-    /// The DoH-enabled server that will cache your requests
-    #[arg(short, long, value_name = "URL")]
-    remote: String,
+    /// A DoH-enabled upstream to query, e.g. https://cloudflare-dns.com/dns-query. May be
+    /// given multiple times to race several upstreams against each other
+    #[arg(short, long, value_name = "URL", required = true)]
+    remote: Vec<String>,
     /// A local addr to bind to (e.g. 127.0.0.1:53)
     #[arg(short, long, value_name = "ADDR", default_value = "127.0.0.1:53")]
     addr_bind: SocketAddr,
     /// Whether to cache requests
     #[arg(short, long, default_value_t = false)]
     cache: bool,
+    /// How many entries the cache may hold before CLOCK eviction kicks in
+    #[arg(short = 's', long = "cache-size", default_value_t = 1024)]
+    cache_size: usize,
+    /// The HTTP method used to query the DoH upstream
+    #[arg(long, value_enum, default_value = "post")]
+    method: DohMethod,
+    /// How long to wait for the upstream DoH request before falling back, in seconds
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+    /// Serve an expired cache entry when the upstream fails instead of falling back
+    #[arg(long, default_value_t = false)]
+    serve_stale: bool,
+    /// A conventional UDP resolver to fall back to if the DoH upstream fails and no
+    /// stale entry is available (e.g. 1.1.1.1:53)
+    #[arg(long, value_name = "ADDR")]
+    fallback_resolver: Option<SocketAddr>,
+    /// A file of blocked domains, one per line, suffix-matched against subdomains
+    #[arg(long, value_name = "PATH")]
+    blocklist: Option<String>,
+    /// How to answer a query for a blocked domain
+    #[arg(long, value_enum, default_value = "nxdomain")]
+    block_mode: BlockMode,
     #[arg(short, long, default_value_t = false)]
     log_queries: bool,
-    #[arg(short = 't', long, default_value_t = 3600)]
-    cache_ttl: u64,
     #[arg(short = 'p', long, default_value = None)]
     proxy: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes `labels` as a sequence of length-prefixed DNS labels terminated by a
+    /// zero-length byte, e.g. `dns_name(&["example", "com"])` for `example.com`.
+    fn dns_name(labels: &[&str]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for label in labels {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+        buf.push(0);
+        buf
+    }
+
+    /// Builds a minimal single-question DNS query.
+    fn build_query(id: u16, name: &[u8], qtype: u16) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&id.to_be_bytes());
+        msg.extend_from_slice(&0x0100u16.to_be_bytes()); // flags: RD
+        msg.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+        msg.extend_from_slice(&0u16.to_be_bytes()); // ANCOUNT
+        msg.extend_from_slice(&0u16.to_be_bytes()); // NSCOUNT
+        msg.extend_from_slice(&0u16.to_be_bytes()); // ARCOUNT
+        msg.extend_from_slice(name);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes()); // QCLASS = IN
+        msg
+    }
+
+    /// Builds a minimal single-question, single-answer DNS response, with the answer's
+    /// NAME as a compression pointer back to the question.
+    fn build_response_with_answer(id: u16, name: &[u8], qtype: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&id.to_be_bytes());
+        msg.extend_from_slice(&0x8180u16.to_be_bytes()); // flags: QR + RD + RA
+        msg.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+        msg.extend_from_slice(&1u16.to_be_bytes()); // ANCOUNT
+        msg.extend_from_slice(&0u16.to_be_bytes()); // NSCOUNT
+        msg.extend_from_slice(&0u16.to_be_bytes()); // ARCOUNT
+        msg.extend_from_slice(name);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes()); // QCLASS = IN
+        msg.extend_from_slice(&0xc00cu16.to_be_bytes()); // NAME = pointer to the question
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&1u16.to_be_bytes()); // CLASS = IN
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        msg.extend_from_slice(rdata);
+        msg
+    }
+
+    #[test]
+    fn remaining_ttl_with_jitter_no_jitter_above_threshold() {
+        assert_eq!(remaining_ttl_with_jitter(100, 10), 90);
+    }
+
+    #[test]
+    fn remaining_ttl_with_jitter_clamps_at_zero() {
+        assert_eq!(remaining_ttl_with_jitter(10, 20), 0);
+    }
+
+    #[test]
+    fn remaining_ttl_with_jitter_shaves_at_most_jitter_max_near_expiry() {
+        let remaining = remaining_ttl_with_jitter(40, 38);
+        assert!(remaining <= 2);
+        let remaining = remaining_ttl_with_jitter(40, 20);
+        assert!((20 - JITTER_MAX_SECS..=20).contains(&remaining));
+    }
+
+    #[test]
+    fn min_answer_ttl_reads_the_answer_ttl() {
+        let name = dns_name(&["example", "com"]);
+        let response = build_response_with_answer(0x1234, &name, 1, 300, &[127, 0, 0, 1]);
+        assert_eq!(min_answer_ttl(&response), Some(300));
+    }
+
+    #[test]
+    fn min_answer_ttl_rejects_a_truncated_message() {
+        assert_eq!(min_answer_ttl(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn rewrite_answer_ttls_overwrites_every_answer_ttl() {
+        let name = dns_name(&["example", "com"]);
+        let mut response = build_response_with_answer(0x1234, &name, 1, 300, &[127, 0, 0, 1]);
+        rewrite_answer_ttls(&mut response, 42).unwrap();
+        assert_eq!(min_answer_ttl(&response), Some(42));
+    }
+
+    #[test]
+    fn cache_insert_and_get_round_trip() {
+        let mut cache = Cache::new(2);
+        cache.insert(b"a".to_vec(), 60, b"payload-a".to_vec());
+        let (_, ttl, payload) = cache.get(b"a").unwrap();
+        assert_eq!(ttl, 60);
+        assert_eq!(payload, b"payload-a");
+    }
+
+    #[test]
+    fn cache_evicts_the_unreferenced_slot_first() {
+        let mut cache = Cache::new(2);
+        cache.insert(b"a".to_vec(), 60, b"a".to_vec());
+        cache.insert(b"b".to_vec(), 60, b"b".to_vec());
+        // Touch "a" so its reference bit is set; "b" is left unreferenced and should be
+        // the CLOCK hand's first eviction target.
+        cache.get(b"a");
+        cache.insert(b"c".to_vec(), 60, b"c".to_vec());
+
+        assert!(cache.get(b"a").is_some());
+        assert!(cache.get(b"b").is_none());
+        assert!(cache.get(b"c").is_some());
+    }
+
+    #[test]
+    fn cache_retain_expired_beyond_keeps_an_entry_still_within_grace() {
+        let mut cache = Cache::new(4);
+        cache.slots.push(Some(CacheEntry {
+            key: b"a".to_vec(),
+            inserted_at: Instant::now() - Duration::from_secs(50),
+            ttl: 10,
+            payload: b"a".to_vec(),
+            referenced: false,
+        }));
+        cache.index.insert(b"a".to_vec(), 0);
+
+        cache.retain_expired_beyond(60);
+        assert!(cache.get(b"a").is_some());
+    }
+
+    #[test]
+    fn cache_retain_expired_beyond_drops_an_entry_past_grace() {
+        let mut cache = Cache::new(4);
+        cache.slots.push(Some(CacheEntry {
+            key: b"a".to_vec(),
+            inserted_at: Instant::now() - Duration::from_secs(80),
+            ttl: 10,
+            payload: b"a".to_vec(),
+            referenced: false,
+        }));
+        cache.index.insert(b"a".to_vec(), 0);
+
+        cache.retain_expired_beyond(60);
+        assert!(cache.get(b"a").is_none());
+    }
+
+    #[test]
+    fn blocklist_matches_exact_domain_and_subdomains() {
+        let blocklist = Blocklist {
+            domains: ["example.com".to_string()].into_iter().collect(),
+        };
+        assert!(blocklist.is_blocked("example.com."));
+        assert!(blocklist.is_blocked("ads.example.com."));
+        assert!(!blocklist.is_blocked("example.org."));
+    }
+
+    #[test]
+    fn blocklist_is_blocked_is_case_insensitive() {
+        let blocklist = Blocklist {
+            domains: ["example.com".to_string()].into_iter().collect(),
+        };
+        assert!(blocklist.is_blocked("EXAMPLE.COM."));
+    }
+
+    #[test]
+    fn is_valid_response_accepts_an_answer_matching_the_question() {
+        let name = dns_name(&["example", "com"]);
+        let request = build_query(0xbeef, &name, 1);
+        let response = build_response_with_answer(0xbeef, &name, 1, 60, &[127, 0, 0, 1]);
+        assert!(is_valid_response(&response, &request));
+    }
+
+    #[test]
+    fn is_valid_response_rejects_a_spoofed_answer_for_a_different_name() {
+        let queried_name = dns_name(&["example", "com"]);
+        let spoofed_name = dns_name(&["evil", "attacker"]);
+        let request = build_query(0xbeef, &queried_name, 1);
+        let spoofed_response = build_response_with_answer(0xbeef, &spoofed_name, 1, 60, &[6, 6, 6, 6]);
+        assert!(!is_valid_response(&spoofed_response, &request));
+    }
+
+    #[test]
+    fn is_valid_response_rejects_a_message_without_the_qr_bit_set() {
+        let name = dns_name(&["example", "com"]);
+        let request = build_query(0xbeef, &name, 1);
+        // A query, not a response -- the QR bit is unset.
+        assert!(!is_valid_response(&request, &request));
+    }
+
+    #[test]
+    fn skip_name_rejects_a_truncated_label() {
+        // Claims a 10-byte label but the buffer ends after the length byte.
+        assert_eq!(skip_name(&[10, b'a'], 0), None);
+    }
+}